@@ -9,7 +9,9 @@ use std::mem::size_of;
 use structopt::StructOpt;
 
 use ncs::messaging::{NCSyncKind, NCSyncMessage};
+use next_client_win::exclude::{self, ExcludePreview};
 use std::convert::Into;
+use std::fs;
 use std::path::PathBuf;
 
 #[macro_use]
@@ -46,6 +48,36 @@ enum Command {
         /// using stash to save local files
         stash: bool,
     },
+    #[structopt(name = "exclude")]
+    /// exclude settings
+    Exclude {
+        #[structopt(subcommand)]
+        command: ExcludeCommand,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ExcludeCommand {
+    #[structopt(name = "preview")]
+    /// show what a candidate excludes.json would change, without saving it
+    Preview {
+        #[structopt(parse(from_os_str))]
+        /// the candidate excludes.json
+        candidate: PathBuf,
+        #[structopt(short = "d", long = "dir", parse(from_os_str))]
+        /// a folder in the synchronized folder [default: current directory]
+        dir: Option<PathBuf>,
+    },
+    #[structopt(name = "lint")]
+    /// check excludes.json for errors and patterns likely to match more than meant
+    Lint {
+        #[structopt(parse(from_os_str))]
+        /// excludes.json to check [default: the one of the synchronized folder]
+        file: Option<PathBuf>,
+        #[structopt(short = "d", long = "dir", parse(from_os_str))]
+        /// a folder in the synchronized folder [default: current directory]
+        dir: Option<PathBuf>,
+    },
 }
 
 impl Into<Vec<NCSyncMessage>> for Command {
@@ -71,6 +103,7 @@ impl Into<Vec<NCSyncMessage>> for Command {
                 is_recursive = recursive;
                 use_stash = stash;
             }
+            Self::Exclude { .. } => return Vec::new(),
         }
         let mut messages = Vec::new();
         for path in pths {
@@ -101,6 +134,10 @@ fn main() -> CliResult {
     let args = NCSync::from_args();
     args.verbose.setup_env_logger("ncsync")?;
 
+    if let Command::Exclude { command } = args.command {
+        return exclude_command(command);
+    }
+
     let messages: Vec<NCSyncMessage> = args.command.into();
     unsafe {
         send_messages(messages)?;
@@ -109,6 +146,86 @@ fn main() -> CliResult {
     Ok(())
 }
 
+fn exclude_command(command: ExcludeCommand) -> CliResult {
+    match command {
+        ExcludeCommand::Preview { candidate, dir } => {
+            let root = local_root(dir)?;
+            let candidate = fs::read_to_string(&candidate)?;
+            let preview = ExcludePreview::new(&root, &candidate)
+                .map_err(|e| failure::err_msg(e.to_string()))?;
+
+            for warning in preview.warnings.iter() {
+                println!("{}", warning);
+            }
+            println!("{}", root.display());
+            println!("{} paths start syncing:", preview.starts.len());
+            for p in preview.starts.iter() {
+                println!("  + {}", p.display());
+            }
+            println!("{} paths stop syncing:", preview.stops.len());
+            for p in preview.stops.iter() {
+                println!("  - {}", p.display());
+            }
+            println!(
+                "upload: {}, download: {}",
+                human_size(preview.upload),
+                human_size(preview.download)
+            );
+            if preview.unknown_sizes > 0 {
+                println!(
+                    "({} files on the server are not counted: their sizes are unknown.)",
+                    preview.unknown_sizes
+                );
+            }
+        }
+        ExcludeCommand::Lint { file, dir } => {
+            let file = match file {
+                Some(f) => f,
+                None => local_root(dir)?.join(".ncs").join("excludes.json"),
+            };
+            let issues = exclude::lint(&fs::read_to_string(&file)?);
+            for issue in issues.iter() {
+                println!("{}: {}", file.display(), issue);
+            }
+            let errors = issues.iter().filter(|issue| issue.is_error).count();
+            if errors > 0 {
+                return Err(failure::format_err!(
+                    "{} errors. The daemon refuses this excludes.json.",
+                    errors
+                )
+                .into());
+            }
+            println!("{} warnings.", issues.len());
+        }
+    }
+
+    Ok(())
+}
+
+fn local_root(dir: Option<PathBuf>) -> Result<PathBuf, failure::Error> {
+    let dir = match dir {
+        Some(d) => d,
+        None => std::env::current_dir()?,
+    };
+    exclude::local_root_of(&dir.canonicalize()?)
+        .ok_or_else(|| failure::err_msg("Not in a synchronized folder."))
+}
+
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
 unsafe fn send_messages(messages: Vec<NCSyncMessage>) -> CliResult {
     // let target_hwnd = FindWindowA("ncclient", "NextcloudClientWindow");
     let target_hwnd = FindWindowA("ncclient", "NCWindow");
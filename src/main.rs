@@ -7,12 +7,13 @@ use ncs::meta::*;
 use ncs::nc_listen::*;
 use ncs::network::{self, NetworkStatus};
 use ncs::*;
-use next_client_win::{config, logging, ncsync_daemon};
+use next_client_win::{config, exclude, logging, ncsync_daemon, remote, retry};
 use notify::{watcher, RecursiveMode, Watcher};
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc as std_mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration as StdDuration;
 use tokio::sync::mpsc as tokio_mpsc;
 #[allow(unused)]
@@ -33,153 +34,696 @@ macro_rules! error_send {
     };
 }
 
-async fn run(
-    tray_tx: tokio_mpsc::Sender<TasktrayMessage>,
-    tray_rx: Arc<Mutex<tokio_mpsc::Receiver<TasktrayMessage>>>,
-    ncsyncmes_tx: tokio_mpsc::Sender<Option<NCSyncMessage>>,
-    ncsyncmes_rx: Arc<Mutex<tokio_mpsc::Receiver<Option<NCSyncMessage>>>>,
-    icon_tx: tokio_mpsc::Sender<IconChange>,
-    log_handle: &log4rs::Handle,
-    loop_counter: u32,
-    config: &config::Config,
-    repair_boot: bool,
-) -> Result<bool> {
-    icon_tx.send(IconChange::Load).await.ok();
+/// Everything `run` holds for one sync pair of conf.ini.
+struct SyncPair {
+    name: String,
+    // WebDAV root of this pair is the account root followed by REMOTE_ROOT.
+    nc_info: NCInfo,
+    pair_config: config::PairConfig,
+    local_info: LocalInfo,
+    // the copy of `local_info` which ncsync messages are routed and pushed with.
+    routed_local_info: Arc<RwLock<LocalInfo>>,
+    excludes: exclude::PairExcludes,
+    public_resource: Arc<Mutex<PublicResource>>,
+    com_tx: tokio_mpsc::Sender<Command>,
+    network_status: NetworkStatus,
+    offline_locevent_que: Vec<local_listen::LocalEvent>,
+    retry_que: retry::RetryQueue<RetryKey, RetryItem>,
+    local_watching: LocalWatching,
+    nclisten_handle: tokio::task::JoinHandle<()>,
+}
 
-    match config.validation().await? {
-        config::ValidateResult::Ok => (),
-        config::ValidateResult::RootPathError => {
-            return Err(anyhow!("[config error] Please fix ROOT_PATH in conf.ini"));
+/// Watchers of LOCAL_ROOT and `.ncs` of a pair, and the tasks reading them.
+struct LocalWatching {
+    watchers: Vec<notify::RecommendedWatcher>,
+    handles: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl LocalWatching {
+    fn stop(&mut self) {
+        self.watchers.clear();
+        for handle in self.handles.drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+/// notify -> exclude filter -> ncs. Started again when the exclude rules change,
+/// because ncs keeps the LocalInfo given here.
+fn spawn_local_watching(
+    name: &str,
+    local_info: &LocalInfo,
+    nc_info: &NCInfo,
+    excludes: &exclude::PairExcludes,
+    com_tx: &tokio_mpsc::Sender<Command>,
+) -> Result<LocalWatching> {
+    let (raw_tx, raw_rx) = std_mpsc::channel();
+    let mut root_watcher = watcher(raw_tx, StdDuration::from_secs(5)).unwrap();
+    root_watcher.watch(&local_info.root_path, RecursiveMode::Recursive)?;
+    let (tx, rx) = std_mpsc::channel();
+    excludes.clone().spawn_event_filter(
+        raw_rx,
+        tx,
+        vec![
+            local_info.root_path.clone(),
+            local_info.root_path_cano.clone(),
+        ],
+    );
+    let loceve_rx = Mutex::new(rx);
+
+    let (tx, rx) = std_mpsc::channel();
+    let mut meta_watcher = watcher(tx, StdDuration::from_secs(5)).unwrap();
+    meta_watcher.watch(
+        local_info.get_metadir_name().as_str(),
+        RecursiveMode::Recursive,
+    )?;
+    let metaeve_rx = Mutex::new(rx);
+
+    let tx = com_tx.clone();
+    let lci = local_info.clone();
+    let nci = nc_info.clone();
+    let pair_name = name.to_string();
+    let watching_handle = tokio::spawn(logging::with_context(async move {
+        logging::set_context(Some(&pair_name), Some("local_watch"), None);
+        let res = watching(tx.clone(), loceve_rx, &lci, &nci).await;
+        if let Err(e) = res {
+            error_send!(tx, e);
+        }
+    }));
+
+    let tx = com_tx.clone();
+    let lci = local_info.clone();
+    let pair_name = name.to_string();
+    let updateexcfile_handle = tokio::spawn(logging::with_context(async move {
+        logging::set_context(Some(&pair_name), Some("exclude_watch"), None);
+        let res = exc_list_update_watching(tx.clone(), metaeve_rx, &lci).await;
+        if let Err(e) = res {
+            error_send!(tx, e);
         }
-        config::ValidateResult::DontUseSSLError => {
-            return Err(anyhow!(
-                "[config error] NC Host must use SSL/TLS. Please set \"https://...\" to NC_HOST."
-            ));
+    }));
+
+    Ok(LocalWatching {
+        watchers: vec![root_watcher, meta_watcher],
+        handles: vec![watching_handle, updateexcfile_handle],
+    })
+}
+
+/// An operation which is sent to the pair again when it fails.
+/// ncs handles an event as a whole, so a retry repeats the whole command.
+#[derive(Debug)]
+enum RetryItem {
+    Local(local_listen::LocalEvent),
+    // NCEvents can't be replayed once nc_state moved on, so the folder is pulled instead.
+    Pull {
+        target: PathBuf,
+        is_recursive: bool,
+        stash: bool,
+    },
+}
+
+/// Which operation a `RetryItem` is. The same operation sent again has the same key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RetryKey {
+    Create(PathBuf),
+    Delete(PathBuf),
+    Modify(PathBuf),
+    Move(PathBuf, PathBuf),
+    Pull { target: PathBuf, is_recursive: bool },
+}
+
+impl fmt::Display for RetryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryKey::Create(p) => write!(f, "create {:?}", p),
+            RetryKey::Delete(p) => write!(f, "delete {:?}", p),
+            RetryKey::Modify(p) => write!(f, "modify {:?}", p),
+            RetryKey::Move(from, to) => write!(f, "move {:?} -> {:?}", from, to),
+            RetryKey::Pull {
+                target,
+                is_recursive,
+            } => write!(f, "pull {:?} (-r: {})", target, is_recursive),
+        }
+    }
+}
+
+impl RetryItem {
+    fn key(&self) -> RetryKey {
+        match self {
+            RetryItem::Local(LocalEvent::Create(p)) => RetryKey::Create(p.clone()),
+            RetryItem::Local(LocalEvent::Delete(p)) => RetryKey::Delete(p.clone()),
+            RetryItem::Local(LocalEvent::Modify(p)) => RetryKey::Modify(p.clone()),
+            RetryItem::Local(LocalEvent::Move(from, to)) => {
+                RetryKey::Move(from.clone(), to.clone())
+            }
+            // a pull with the stash and one without do the same to the server.
+            RetryItem::Pull {
+                target,
+                is_recursive,
+                ..
+            } => RetryKey::Pull {
+                target: target.clone(),
+                is_recursive: *is_recursive,
+            },
         }
-        config::ValidateResult::NetworkError => {
+    }
+
+    fn into_command(self) -> Command {
+        match self {
+            RetryItem::Local(ev) => Command::LocEvent(ev),
+            RetryItem::Pull {
+                target,
+                is_recursive,
+                stash,
+            } => Command::PullEvent {
+                target,
+                is_recursive,
+                stash,
+            },
+        }
+    }
+}
+
+/// Activities are reported with paths of the whole account. A pair with REMOTE_ROOT
+/// gets the events in it with paths from REMOTE_ROOT, and a move across it
+/// becomes a creation or a deletion.
+fn scope_ncevents(pair_config: &config::PairConfig, ev_vec: Vec<NCEvent>) -> Vec<NCEvent> {
+    if !pair_config.is_scoped() {
+        return ev_vec;
+    }
+
+    let scoped = |p: &str| pair_config.scoped_path(p);
+    let len = ev_vec.len();
+    let ev_vec = ev_vec
+        .into_iter()
+        .filter_map(|ev| match ev {
+            NCEvent::Create(p) => scoped(&p).map(NCEvent::Create),
+            NCEvent::Delete(p) => scoped(&p).map(NCEvent::Delete),
+            NCEvent::Modify(p) => scoped(&p).map(NCEvent::Modify),
+            NCEvent::Move(from, to) => match (scoped(&from), scoped(&to)) {
+                (Some(from), Some(to)) => Some(NCEvent::Move(from, to)),
+                (Some(from), None) => Some(NCEvent::Delete(from)),
+                (None, Some(to)) => Some(NCEvent::Create(to)),
+                (None, None) => None,
+            },
+        })
+        .collect::<Vec<_>>();
+    debug!(
+        "[{}] {} of {} events are in {}",
+        pair_config.name,
+        ev_vec.len(),
+        len,
+        pair_config.remote_root
+    );
+
+    ev_vec
+}
+
+/// "/src/main.rs" -> "src/main.rs"
+fn managed_path_of(nc_path: &str) -> PathBuf {
+    nc_path.split('/').filter(|n| !n.is_empty()).collect()
+}
+
+/// `judge` of the pair for a path in LOCAL_ROOT. A path not in it yet is judged as a file.
+fn judge_local(pair: &SyncPair, nc_path: &str) -> bool {
+    let managed_path = managed_path_of(nc_path);
+    let is_dir = pair.local_info.root_path_cano.join(&managed_path).is_dir();
+    pair.excludes.judge(managed_path, is_dir)
+}
+
+/// `judge` of the pair for a path changed on the server. The server is asked for
+/// the type and the size only if the rules need the size of the file.
+async fn judge_remote(pair: &SyncPair, client: &reqwest::Client, nc_path: &str) -> Result<bool> {
+    if !pair.excludes.needs_size() {
+        return Ok(judge_local(pair, nc_path));
+    }
+    match remote::stat(client, &pair.nc_info, &managed_path_of(nc_path)).await? {
+        Some(entry) => Ok(pair.excludes.judge_remote(&entry)),
+        // already gone from the server. ncs finds it out.
+        None => Ok(judge_local(pair, nc_path)),
+    }
+}
+
+/// Drops the server events on the paths the pair excludes, so nothing excluded is downloaded.
+async fn exclude_ncevents(
+    pair: &SyncPair,
+    client: &reqwest::Client,
+    ev_vec: Vec<NCEvent>,
+) -> Result<Vec<NCEvent>> {
+    let mut included = Vec::with_capacity(ev_vec.len());
+    for ev in ev_vec {
+        let ev = match ev {
+            NCEvent::Create(p) => match judge_remote(pair, client, &p).await? {
+                true => Some(NCEvent::Create(p)),
+                false => None,
+            },
+            NCEvent::Modify(p) => match judge_remote(pair, client, &p).await? {
+                true => Some(NCEvent::Modify(p)),
+                false => None,
+            },
+            // the copy in LOCAL_ROOT of an excluded path is kept.
+            NCEvent::Delete(p) => match judge_local(pair, &p) {
+                true => Some(NCEvent::Delete(p)),
+                false => None,
+            },
+            // moved out of or into the synchronized paths.
+            NCEvent::Move(from, to) => {
+                match (
+                    judge_local(pair, &from),
+                    judge_remote(pair, client, &to).await?,
+                ) {
+                    (true, true) => Some(NCEvent::Move(from, to)),
+                    (true, false) => Some(NCEvent::Delete(from)),
+                    (false, true) => Some(NCEvent::Create(to)),
+                    (false, false) => None,
+                }
+            }
+        };
+        match ev {
+            Some(ev) => included.push(ev),
+            None => debug!("[{}] server event on excluded path is dropped.", pair.name),
+        }
+    }
+
+    Ok(included)
+}
+
+/// `refresh` targets of a pull of `target`, leaving out the paths the pair excludes.
+async fn pull_targets(
+    pair: &SyncPair,
+    client: &reqwest::Client,
+    target: PathBuf,
+    is_recursive: bool,
+) -> Result<Vec<(PathBuf, bool)>> {
+    let root = &pair.local_info.root_path_cano;
+    let managed_path = match target.strip_prefix(root) {
+        Ok(p) => p.to_path_buf(),
+        Err(_) => return Ok(vec![(target, is_recursive)]),
+    };
+    let targets = pair
+        .excludes
+        .pull_targets(client, &pair.nc_info, &managed_path, is_recursive)
+        .await?;
+    debug!("[{}] Pull {:?} -> {:?}", pair.name, managed_path, targets);
+
+    Ok(targets
+        .into_iter()
+        .map(|(p, is_recursive)| (root.join(p), is_recursive))
+        .collect())
+}
+
+/// The operation and the path written to the JSON logs while `command` is handled.
+fn log_context_of(command: &Command) -> (&'static str, Option<&Path>) {
+    match command {
+        Command::LocEvent(LocalEvent::Modify(p)) => ("local_event", Some(p)),
+        Command::LocEvent(_) => ("local_event", None),
+        Command::NCEvents(..) => ("server_events", None),
+        Command::PullEvent { target, .. } => ("pull", Some(target)),
+        Command::UpdateExcFile => ("update_excludes", None),
+        Command::UpdateConfigFile => ("update_config", None),
+        Command::HardRepair => ("hard_repair", None),
+        Command::NormalRepair => ("normal_repair", None),
+        Command::NetworkConnect => ("network_connect", None),
+        Command::NetworkDisconnect => ("network_disconnect", None),
+        Command::Terminate(_) => ("terminate", None),
+        Command::Error(_) => ("error", None),
+    }
+}
+
+/// Sends `item` to the pair again after a backoff. Returns false when it is parked or given up.
+fn schedule_retry(
+    pair: &mut SyncPair,
+    item: RetryItem,
+    policy: &retry::RetryPolicy,
+    client: &reqwest::Client,
+    nc_host: &str,
+) -> bool {
+    let key = item.key();
+    let (item, failures) = match pair.retry_que.fail(&key, item, policy) {
+        retry::Failure::Retry(item, failures) => (item, failures),
+        retry::Failure::Parked => {
             warn!(
-                "[config error] host, username or password are wrong or Network is disconnect.
-Please fix conf.ini and connect to the Internet."
+                "[{}] {} failed {} times. parked until the server answers again.",
+                pair.name, key, policy.max_attempts
+            );
+            return false;
+        }
+        retry::Failure::GivenUp => {
+            error!(
+                "[{}] {} still fails after replays. pull or push it by ncsync, or repair.",
+                pair.name, key
             );
+            error!(
+                "[{}] given up: {}",
+                pair.name,
+                pair.retry_que
+                    .given_up()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            return false;
         }
+    };
+    let tx = pair.com_tx.clone();
+    let name = pair.name.clone();
+    let policy = policy.clone();
+    let client = client.clone();
+    let nc_host = nc_host.to_string();
+    tokio::spawn(logging::with_context(async move {
+        logging::set_context(Some(&name), Some("retry"), None);
+        let retry_after = retry::server_retry_after(&client, &nc_host).await;
+        let delay = policy.delay(failures, retry_after);
+        info!(
+            "[{}] retry {}/{} in {:?}: {}",
+            name,
+            failures + 1,
+            policy.max_attempts,
+            delay,
+            key
+        );
+        sleep(delay).await;
+        tx.send(item.into_command()).await.ok();
+    }));
+    true
+}
+
+/// Replaces the LocalInfo of the pair, for the ncsync messages as well.
+fn set_local_info(pair: &mut SyncPair, local_info: LocalInfo) {
+    match pair.routed_local_info.write() {
+        Ok(mut routed) => *routed = local_info.clone(),
+        Err(e) => *e.into_inner() = local_info.clone(),
     }
+    pair.local_info = local_info;
+}
 
-    // Can't update these environment variables by rerun.
-    let username = config.nc_username.clone();
-    let password = config.nc_password.clone();
-    let host = config.nc_host.clone();
-    let host = fix_host(&host);
+/// Sends the parked items of the pair again.
+fn replay_parked(pair: &mut SyncPair) {
+    let items = pair.retry_que.take_parked();
+    if items.is_empty() {
+        return;
+    }
+    info!("[{}] replay {} parked items.", pair.name, items.len());
+    let tx = pair.com_tx.clone();
+    tokio::spawn(async move {
+        for item in items {
+            tx.send(item.into_command()).await.ok();
+        }
+    });
+}
 
-    let nc_info = NCInfo::new(username, password, host);
+fn spawn_nclistening(
+    name: String,
+    tx: tokio_mpsc::Sender<Command>,
+    nc_info: NCInfo,
+    local_info: LocalInfo,
+    nc_state: NCState,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(logging::with_context(async move {
+        logging::set_context(Some(&name), Some("server_listen"), None);
+        sleep(Duration::from_secs(3)).await;
+        let res = nclistening(tx.clone(), &nc_info, &local_info, nc_state.clone()).await;
+        if let Err(e) = res {
+            error_send!(tx, e);
+        }
+    }))
+}
 
-    let local_root_path = config.local_root.clone();
-    let client = config.make_client()?;
-    let mut local_info = LocalInfo::new(local_root_path, client.clone())?;
-    local_info.set_autostash_keep_span(config.autostash_keep_span);
-    let local_info = local_info;
+fn restart_nclistening(pair: &mut SyncPair) -> Result<()> {
+    pair.nclisten_handle.abort();
+    let nc_state = {
+        let pr_ref = pair.public_resource.lock().map_err(|_| LockError)?;
+        pr_ref.nc_state.clone()
+    };
+    pair.nclisten_handle = spawn_nclistening(
+        pair.name.clone(),
+        pair.com_tx.clone(),
+        pair.nc_info.clone(),
+        pair.local_info.clone(),
+        nc_state,
+    );
 
-    let logfile_path = local_info.get_logfile_name();
-    logging::prepare_logging(log_handle, logfile_path, config)?;
+    Ok(())
+}
+
+/// Starts the watchers and the listener again with the current LocalInfo of the pair.
+fn restart_watching(pair: &mut SyncPair) -> Result<()> {
+    pair.local_watching.stop();
+    pair.local_watching = spawn_local_watching(
+        &pair.name,
+        &pair.local_info,
+        &pair.nc_info,
+        &pair.excludes,
+        &pair.com_tx,
+    )?;
+    restart_nclistening(pair)
+}
 
-    let public_resource: PublicResource;
+/// Settled status of each pair. The tray icon shows the worst of them.
+struct StatusBoard {
+    names: Vec<String>,
+    statuses: Vec<IconChange>,
+}
+
+impl StatusBoard {
+    fn new(names: Vec<String>) -> Self {
+        let statuses = names.iter().map(|_| IconChange::Normal).collect();
+        Self { names, statuses }
+    }
+
+    fn set(&mut self, idx: usize, status: IconChange) {
+        if self.statuses[idx] != status {
+            info!(
+                "[{}] status: {:?} -> {:?}",
+                self.names[idx], self.statuses[idx], status
+            );
+            self.statuses[idx] = status;
+        }
+    }
+
+    fn overall(&self) -> IconChange {
+        if self.statuses.contains(&IconChange::Error) {
+            IconChange::Error
+        } else if self.statuses.contains(&IconChange::Offline) {
+            IconChange::Offline
+        } else {
+            IconChange::Normal
+        }
+    }
+}
+
+async fn load_public_resource(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    client: &reqwest::Client,
+) -> Result<PublicResource> {
     if Path::new(local_info.get_cachefile_name().as_str()).exists() {
         // load cache
-        let ncs_cache = load_cache(&local_info)?;
+        let ncs_cache = load_cache(local_info)?;
         let nc_state = NCState {
             latest_activity_id: ncs_cache.latest_activity_id,
         };
         let root_entry = json_entry2entry(ncs_cache.root_entry)?;
-        public_resource = PublicResource::new(root_entry, nc_state);
+        Ok(PublicResource::new(root_entry, nc_state))
     } else {
         // init
-        if !network::is_online(&nc_info, &client).await {
+        if !network::is_online(nc_info, client).await {
             return Err(NetworkOfflineError.into());
         }
 
-        let (root, latest_activity_id) = init(&nc_info, &local_info).await?;
+        let (root, latest_activity_id) = init(nc_info, local_info).await?;
         let json_entry = {
             let root_ref = root.lock().map_err(|_| LockError)?;
             root2json_entry(&root_ref)?
         };
-        save_cache(latest_activity_id.clone(), json_entry, &local_info)?;
+        save_cache(latest_activity_id.clone(), json_entry, local_info)?;
         let nc_state = NCState {
             latest_activity_id: latest_activity_id,
         };
-        public_resource = PublicResource::new(root, nc_state);
+        Ok(PublicResource::new(root, nc_state))
     }
+}
 
+/// Loads the tree of a pair and starts its watchers and listener.
+/// The commands of the pair reach the main loop tagged with `idx`.
+async fn start_pair(
+    idx: usize,
+    pair_config: &config::PairConfig,
+    local_info: LocalInfo,
+    nc_info: &NCInfo,
+    client: &reqwest::Client,
+    main_tx: &tokio_mpsc::Sender<(usize, Command)>,
+) -> Result<SyncPair> {
+    info!(
+        "[{}] {:?} <-> {}",
+        pair_config.name, local_info.root_path, pair_config.remote_root
+    );
+    let mut nc_info = nc_info.clone();
+    nc_info.root_path = pair_config.scoped_root_path(&nc_info.root_path);
+    let public_resource = load_public_resource(&nc_info, &local_info, client).await?;
     let public_resource = Arc::new(Mutex::new(public_resource));
+    let excludes = exclude::PairExcludes::new(pair_config, &local_info)?;
+    let network_status = network::status(&nc_info, client).await?;
 
-    // to end with successful completion, watchers must be managed here.
+    let (com_tx, mut com_rx) = tokio_mpsc::channel(32);
 
-    let (tx, rx) = std_mpsc::channel();
-    let mut root_watcher = watcher(tx, StdDuration::from_secs(5)).unwrap();
-    root_watcher.watch(&local_info.root_path, RecursiveMode::Recursive)?;
-    let loceve_rx = Mutex::new(rx);
+    // tag the commands of this pair for the main loop.
+    let tx = main_tx.clone();
+    let _forward_handle = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                c = com_rx.recv() => match c {
+                    Some(c) => {
+                        if tx.send((idx, c)).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+                _ = tx.closed() => break,
+            }
+        }
+    });
 
-    let (tx, rx) = std_mpsc::channel();
-    let mut meta_watcher = watcher(tx, StdDuration::from_secs(5)).unwrap();
-    meta_watcher.watch(
-        local_info.get_metadir_name().as_str(),
-        RecursiveMode::Recursive,
-    )?;
-    let metaeve_rx = Mutex::new(rx);
+    let local_watching =
+        spawn_local_watching(&pair_config.name, &local_info, &nc_info, &excludes, &com_tx)?;
 
-    let (tx, rx) = std_mpsc::channel();
-    let mut ini_watcher = watcher(tx, StdDuration::from_secs(5)).unwrap();
-    ini_watcher.watch(".", RecursiveMode::NonRecursive)?;
-    let ini_rx = Mutex::new(rx);
+    let nc_state = {
+        let pr_ref = public_resource.lock().map_err(|_| LockError)?;
+        pr_ref.nc_state.clone()
+    };
+    let nclisten_handle = spawn_nclistening(
+        pair_config.name.clone(),
+        com_tx.clone(),
+        nc_info.clone(),
+        local_info.clone(),
+        nc_state,
+    );
 
-    let (com_tx, mut com_rx) = tokio_mpsc::channel(32);
+    Ok(SyncPair {
+        name: pair_config.name.clone(),
+        nc_info,
+        pair_config: pair_config.clone(),
+        routed_local_info: Arc::new(RwLock::new(local_info.clone())),
+        local_info,
+        excludes,
+        public_resource,
+        com_tx,
+        network_status,
+        offline_locevent_que: Vec::new(),
+        retry_que: retry::RetryQueue::default(),
+        local_watching,
+        nclisten_handle,
+    })
+}
 
-    if repair_boot && loop_counter == 1 {
-        com_tx.send(Command::NormalRepair).await.ok();
-    }
+async fn run(
+    tray_tx: tokio_mpsc::Sender<TasktrayMessage>,
+    tray_rx: Arc<Mutex<tokio_mpsc::Receiver<TasktrayMessage>>>,
+    ncsyncmes_tx: tokio_mpsc::Sender<Option<NCSyncMessage>>,
+    ncsyncmes_rx: Arc<Mutex<tokio_mpsc::Receiver<Option<NCSyncMessage>>>>,
+    icon_tx: tokio_mpsc::Sender<IconChange>,
+    log_handle: &log4rs::Handle,
+    loop_counter: u32,
+    config: &config::Config,
+    repair_boot: bool,
+) -> Result<bool> {
+    icon_tx.send(IconChange::Load).await.ok();
 
-    let tx = com_tx.clone();
-    let lci = local_info.clone();
-    let nci = nc_info.clone();
-    let _watching_handle = tokio::spawn(async move {
-        let res = watching(tx.clone(), loceve_rx, &lci, &nci).await;
-        if let Err(e) = res {
-            error_send!(tx, e);
+    match config.validation().await? {
+        config::ValidateResult::Ok => (),
+        res if res.is_fatal() => {
+            return Err(anyhow!("[config error] {}", res));
         }
-    });
+        res => {
+            warn!("[network error] {}", res);
+        }
+    }
 
-    let tx = com_tx.clone();
-    let lci = local_info.clone();
-    let _updateexcfile_handle = tokio::spawn(async move {
-        let res = exc_list_update_watching(tx.clone(), metaeve_rx, &lci).await;
-        if let Err(e) = res {
-            error_send!(tx, e);
+    // Can't update these environment variables by rerun.
+    let username = config.nc_username.clone();
+    let password = config.nc_password.clone();
+    let host = config.nc_host.clone();
+    let host = fix_host(&host);
+
+    let nc_info = NCInfo::new(username, password, host);
+
+    let mut client = config.make_client()?;
+
+    // a pair which can't be started is reported, and the others go on.
+    let mut local_infos = Vec::new();
+    let mut failed_pairs = Vec::new();
+    for pair_config in config.pairs.iter() {
+        let local_root_path = pair_config.local_root.clone();
+        match LocalInfo::new(local_root_path, client.clone()) {
+            Ok(mut local_info) => {
+                local_info.set_autostash_keep_span(config.autostash_keep_span);
+                local_infos.push((pair_config, local_info));
+            }
+            Err(e) => {
+                error!("[{}] The pair is not started: {:?}", pair_config.name, e);
+                failed_pairs.push(pair_config.name.clone());
+            }
         }
-    });
+    }
 
-    let tx = com_tx.clone();
-    let _updateconfigfile_handle = tokio::spawn(async move {
-        let res = config::inifile_watching(tx.clone(), ini_rx).await;
-        if let Err(e) = res {
-            error_send!(tx, e);
+    let logfile_paths = local_infos
+        .iter()
+        .map(|(p, l)| (p.name.clone(), logging::pair_logfile(&l.get_logfile_name())))
+        .collect::<Vec<_>>();
+    logging::prepare_logging(log_handle, &logfile_paths, config)?;
+
+    let (main_tx, mut main_rx) = tokio_mpsc::channel::<(usize, Command)>(32);
+
+    // to end with successful completion, watchers must be managed here.
+    let mut watchers = Vec::new();
+    let mut pairs: Vec<SyncPair> = Vec::new();
+
+    for (pair_config, local_info) in local_infos {
+        let idx = pairs.len();
+        match start_pair(idx, pair_config, local_info, &nc_info, &client, &main_tx).await {
+            Ok(pair) => pairs.push(pair),
+            Err(e) => {
+                error!("[{}] The pair is not started: {:?}", pair_config.name, e);
+                failed_pairs.push(pair_config.name.clone());
+            }
         }
-    });
+    }
+    if pairs.is_empty() {
+        return Err(anyhow!("No pair could be started."));
+    }
 
-    let nc_state = {
-        let pr_ref = public_resource.lock().map_err(|_| LockError)?;
-        pr_ref.nc_state.clone()
+    let (tx, rx) = std_mpsc::channel();
+    let mut ini_watcher = watcher(tx, StdDuration::from_secs(5)).unwrap();
+    let conffile_dir = match config::conffile_path().parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
     };
-    let tx = com_tx.clone();
-    let nci = nc_info.clone();
-    let lci = local_info.clone();
-    let _nclisten_handle = tokio::spawn(async move {
-        sleep(Duration::from_secs(3)).await;
-        let res = nclistening(tx.clone(), &nci, &lci, nc_state.clone()).await;
+    ini_watcher.watch(conffile_dir, RecursiveMode::NonRecursive)?;
+    let ini_rx = Mutex::new(rx);
+    watchers.push(ini_watcher);
+
+    // Repair, Restart, Exit and config changes concern all pairs,
+    // so they go through the channel of the first pair.
+    let global_tx = pairs[0].com_tx.clone();
+
+    if repair_boot && loop_counter == 1 {
+        global_tx.send(Command::NormalRepair).await.ok();
+    }
+
+    let tx = global_tx.clone();
+    let _updateconfigfile_handle = tokio::spawn(async move {
+        let res = config::inifile_watching(tx.clone(), ini_rx).await;
         if let Err(e) = res {
             error_send!(tx, e);
         }
     });
 
-    let tx = com_tx.clone();
-    let lci = local_info.clone();
+    let tx = global_tx.clone();
+    let exc_files = pairs
+        .iter()
+        .map(|p| p.local_info.get_excludefile_name())
+        .collect::<Vec<_>>();
     let _control_handle = tokio::spawn(async move {
         sleep(Duration::from_secs(3)).await;
         use tokio_mpsc::error::TryRecvError::*;
@@ -205,8 +749,9 @@ Please fix conf.ini and connect to the Internet."
                         TasktrayMessage::Restart => Command::Terminate(true),
                         TasktrayMessage::Exit => Command::Terminate(false),
                         TasktrayMessage::ExcEdit => {
-                            let exc = lci.get_excludefile_name();
-                            open_notepad(exc);
+                            for exc in exc_files.iter() {
+                                open_notepad(exc.clone());
+                            }
                             continue;
                         }
                         TasktrayMessage::Nop => continue,
@@ -232,8 +777,17 @@ Please fix conf.ini and connect to the Internet."
         }
     });
 
-    let tx = com_tx.clone();
-    let lci = local_info.clone();
+    let routes = pairs
+        .iter()
+        .map(|p| {
+            (
+                p.com_tx.clone(),
+                p.routed_local_info.clone(),
+                p.excludes.clone(),
+                p.pair_config.clone(),
+            )
+        })
+        .collect::<Vec<_>>();
     let _ncsyncmes_handle = tokio::spawn(async move {
         sleep(Duration::from_secs(3)).await;
         use tokio_mpsc::error::TryRecvError::*;
@@ -246,14 +800,33 @@ Please fix conf.ini and connect to the Internet."
                 }
             };
 
-            if tx.is_closed() {
+            if routes[0].0.is_closed() {
                 break;
             }
 
             match r {
                 Some(Ok(Some(m))) => {
                     // debug!("catch: {:?}", m);
-                    let res = ncsync_daemon::forge_event(m, &tx, &lci).await;
+                    // read at each message, since the exclude rules may have been reloaded.
+                    let route = routes.iter().find_map(|(tx, lci, exc, pc)| {
+                        let lci = match lci.read() {
+                            Ok(lci) => lci.clone(),
+                            Err(e) => e.into_inner().clone(),
+                        };
+                        if Path::new(&m.target).starts_with(&lci.root_path_cano) {
+                            Some((tx, lci, exc, pc))
+                        } else {
+                            None
+                        }
+                    });
+                    let (tx, lci, exc, pc) = match route {
+                        Some(r) => r,
+                        None => {
+                            debug!("[ncsync] {:?} is not a managed entity.", m.target);
+                            continue;
+                        }
+                    };
+                    let res = ncsync_daemon::forge_event(m, tx, &lci, exc, pc).await;
                     if let Err(e) = res {
                         error!("NCSM {:?}", e);
                         error_send!(tx, e.into());
@@ -267,77 +840,129 @@ Please fix conf.ini and connect to the Internet."
                     return;
                 }
                 _ => {
-                    error_send!(tx, anyhow!("Something wrong"));
+                    error_send!(routes[0].0, anyhow!("Something wrong"));
                     return;
                 }
             }
         }
     });
 
-    let mut network_status = network::status(&nc_info, &client).await?;
-    let mut nc2l_cancel_map = HashMap::new();
-    let mut l2nc_cancel_set = HashSet::new();
-    let mut offline_locevent_que: Vec<local_listen::LocalEvent> = Vec::new();
+    let mut cancel_tables = pairs
+        .iter()
+        .map(|_| (HashMap::new(), HashSet::new()))
+        .collect::<Vec<_>>();
     let mut retry = Ok(false);
-    let mut current_icon = IconChange::Normal;
-    icon_tx.send(IconChange::Normal).await.ok();
+    let mut hard_repaired = None;
+    // conf.ini changes applied without rebooting.
+    let mut applied_config: Option<config::Config> = None;
+    // the pairs which couldn't be started are listed after the running ones.
+    let mut status_board = StatusBoard::new(
+        pairs
+            .iter()
+            .map(|p| p.name.clone())
+            .chain(failed_pairs.iter().cloned())
+            .collect(),
+    );
+    for idx in pairs.len()..pairs.len() + failed_pairs.len() {
+        status_board.set(idx, IconChange::Error);
+    }
+    let mut retry_policy = config.retry.clone();
+    icon_tx.send(status_board.overall()).await.ok();
     info!("Main Loop Start");
-    while let Some(e) = com_rx.recv().await {
+    while let Some((idx, e)) = main_rx.recv().await {
+        let pair = &mut pairs[idx];
+        let (operation, path) = log_context_of(&e);
+        let pair_name = Some(pair.name.as_str()).filter(|_| operation != "update_config");
+        logging::set_context(pair_name, Some(operation), path);
+        let (nc2l_cancel_map, l2nc_cancel_set) = &mut cancel_tables[idx];
         match e {
-            Command::LocEvent(ev) => match network_status {
+            Command::LocEvent(ev) => match pair.network_status {
                 NetworkStatus::Connect => {
                     icon_tx.send(IconChange::Load).await.ok();
-                    let pr_ref = public_resource.lock().map_err(|_| LockError)?;
+                    let item = RetryItem::Local(ev.clone());
+                    let pr_ref = pair.public_resource.lock().map_err(|_| LockError)?;
                     let res = deal_local_event(
                         ev,
                         &pr_ref.root,
-                        &nc_info,
-                        &local_info,
-                        &mut nc2l_cancel_map,
-                        &mut l2nc_cancel_set,
+                        &pair.nc_info,
+                        &pair.local_info,
+                        nc2l_cancel_map,
+                        l2nc_cancel_set,
                     )
                     .await;
-                    if let Err(e) = res {
-                        error!("L {:?}", e);
-                        icon_tx.send(IconChange::Error).await.ok();
-                        current_icon = IconChange::Error;
-                        continue;
+                    drop(pr_ref);
+                    match res {
+                        Ok(_) => {
+                            pair.retry_que.succeed(&item.key());
+                            replay_parked(pair);
+                            if pair.retry_que.is_clear() {
+                                status_board.set(idx, IconChange::Normal);
+                            }
+                        }
+                        Err(e) => {
+                            error!("[{}] L {:?}", pair.name, e);
+                            if !schedule_retry(pair, item, &retry_policy, &client, &config.nc_host)
+                            {
+                                status_board.set(idx, IconChange::Error);
+                            }
+                        }
                     }
-                    icon_tx.send(current_icon).await.ok();
+                    icon_tx.send(status_board.overall()).await.ok();
                 }
                 NetworkStatus::Disconnect | NetworkStatus::Err(_) => {
-                    info!("LocEvent({:?}) @ offline", ev);
-                    offline_locevent_que.push(ev);
+                    info!("[{}] LocEvent({:?}) @ offline", pair.name, ev);
+                    pair.offline_locevent_que.push(ev);
                 }
             },
-            Command::NCEvents(ev_vec, new_state) => match network_status {
+            Command::NCEvents(ev_vec, new_state) => match pair.network_status {
                 NetworkStatus::Connect => {
                     icon_tx.send(IconChange::Load).await.ok();
-                    info!("NCEvents({:?})", new_state);
-                    let mut pr_ref = public_resource.lock().map_err(|_| LockError)?;
+                    info!("[{}] NCEvents({:?})", pair.name, new_state);
+                    let mut pr_ref = pair.public_resource.lock().map_err(|_| LockError)?;
 
                     if pr_ref.nc_state.eq_or_newer_than(&new_state) {
                         continue;
                     }
 
                     pr_ref.nc_state = new_state;
-                    let res = update_and_download(
-                        ev_vec,
-                        &pr_ref.root,
-                        &nc_info,
-                        &local_info,
-                        &mut nc2l_cancel_map,
-                        &mut l2nc_cancel_set,
-                        false,
-                    )
-                    .await;
-                    if let Err(e) = res {
-                        error!("NC {:?}", e);
-                        icon_tx.send(IconChange::Error).await.ok();
-                        current_icon = IconChange::Error;
-                        continue;
+                    let ev_vec = scope_ncevents(&pair.pair_config, ev_vec);
+                    let res = match exclude_ncevents(pair, &client, ev_vec).await {
+                        Ok(ev_vec) => {
+                            update_and_download(
+                                ev_vec,
+                                &pr_ref.root,
+                                &pair.nc_info,
+                                &pair.local_info,
+                                nc2l_cancel_map,
+                                l2nc_cancel_set,
+                                false,
+                            )
+                            .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    drop(pr_ref);
+                    match res {
+                        Ok(_) => {
+                            replay_parked(pair);
+                            if pair.retry_que.is_clear() {
+                                status_board.set(idx, IconChange::Normal);
+                            }
+                        }
+                        Err(e) => {
+                            error!("[{}] NC {:?}", pair.name, e);
+                            let item = RetryItem::Pull {
+                                target: pair.local_info.root_path_cano.clone(),
+                                is_recursive: true,
+                                stash: false,
+                            };
+                            if !schedule_retry(pair, item, &retry_policy, &client, &config.nc_host)
+                            {
+                                status_board.set(idx, IconChange::Error);
+                            }
+                        }
                     }
-                    icon_tx.send(current_icon).await.ok();
+                    icon_tx.send(status_board.overall()).await.ok();
                 }
                 NetworkStatus::Disconnect | NetworkStatus::Err(_) => {
                     error!("It should be unreachable branch. something wrong.");
@@ -350,89 +975,319 @@ Please fix conf.ini and connect to the Internet."
             } => {
                 icon_tx.send(IconChange::Load).await.ok();
                 info!(
-                    "PullEvent({:?}, -r: {:?}, -s: {:?})",
-                    target, is_recursive, stash
+                    "[{}] PullEvent({:?}, -r: {:?}, -s: {:?})",
+                    pair.name, target, is_recursive, stash
                 );
-                let pr_ref = public_resource.lock().map_err(|_| LockError)?;
-
-                let res = nc_listen::refresh(
-                    target,
+                let item = RetryItem::Pull {
+                    target: target.clone(),
                     is_recursive,
-                    &pr_ref.root,
-                    &nc_info,
-                    &local_info,
-                    &mut nc2l_cancel_map,
                     stash,
-                )
-                .await;
+                };
+                let res = match pull_targets(pair, &client, target, is_recursive).await {
+                    Ok(targets) => {
+                        let pr_ref = pair.public_resource.lock().map_err(|_| LockError)?;
+                        let mut res = Ok(());
+                        for (target, is_recursive) in targets {
+                            res = nc_listen::refresh(
+                                target,
+                                is_recursive,
+                                &pr_ref.root,
+                                &pair.nc_info,
+                                &pair.local_info,
+                                nc2l_cancel_map,
+                                stash,
+                            )
+                            .await;
+                            if res.is_err() {
+                                break;
+                            }
+                        }
+                        res
+                    }
+                    Err(e) => Err(e),
+                };
 
-                if let Err(e) = res {
-                    error!("PULL {:?}", e);
-                    // icon_tx.send(IconChange::Error).ok();
-                    // current_icon = IconChange::Error;
-                    continue;
+                match res {
+                    Ok(_) => {
+                        pair.retry_que.succeed(&item.key());
+                        replay_parked(pair);
+                        if pair.retry_que.is_clear() {
+                            status_board.set(idx, IconChange::Normal);
+                        }
+                    }
+                    Err(e) => {
+                        error!("[{}] PULL {:?}", pair.name, e);
+                        if !schedule_retry(pair, item, &retry_policy, &client, &config.nc_host) {
+                            status_board.set(idx, IconChange::Error);
+                        }
+                    }
                 }
-                icon_tx.send(current_icon).await.ok();
+                icon_tx.send(status_board.overall()).await.ok();
             }
             Command::UpdateExcFile => {
                 icon_tx.send(IconChange::Load).await.ok();
-                info!("Update Exclude targets file.");
-                info!("Rebooting...");
-                retry = Ok(true);
-                break;
+                info!("[{}] Update Exclude targets file.", pair.name);
+                let current = applied_config.as_ref().unwrap_or(config);
+                let pair_config = current
+                    .pairs
+                    .iter()
+                    .find(|pc| pc.name == pair.name)
+                    .unwrap_or(&pair.pair_config)
+                    .clone();
+                // ncs reads blacks and whites when LocalInfo is made.
+                let new_rules = LocalInfo::new(pair_config.local_root.clone(), client.clone())
+                    .and_then(|l| {
+                        let excludes = exclude::PairExcludes::new(&pair_config, &l)?;
+                        Ok((l, excludes))
+                    });
+                let (mut local_info, excludes) = match new_rules {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("[{}] excludes.json is not applied: {}", pair.name, e);
+                        icon_tx.send(status_board.overall()).await.ok();
+                        continue;
+                    }
+                };
+                local_info.set_autostash_keep_span(current.autostash_keep_span);
+
+                // the server is known from the cached tree, so only the changed paths are
+                // asked to it, by the pulls below.
+                let change = {
+                    let pr_ref = pair.public_resource.lock().map_err(|_| LockError)?;
+                    let (old_checker, old_excludes) =
+                        (&pair.local_info.exc_checker, &pair.excludes);
+                    let new_checker = &local_info.exc_checker;
+                    exclude::ExcludePreview::of_reload(
+                        &local_info.root_path_cano,
+                        &pr_ref.root,
+                        &|e| old_checker.judge(&e.managed_path) && old_excludes.judge_remote(e),
+                        &|e| new_checker.judge(&e.managed_path) && excludes.judge_remote(e),
+                    )
+                };
+                let change = match change {
+                    Ok(change) => change,
+                    Err(e) => {
+                        warn!("[{}] excludes.json is not applied: {}", pair.name, e);
+                        icon_tx.send(status_board.overall()).await.ok();
+                        continue;
+                    }
+                };
+                pair.excludes.replace(&excludes);
+                set_local_info(pair, local_info);
+                let restarted = restart_watching(pair);
+                if let Err(e) = restarted {
+                    error!("[{}] The pair is stopped: {:?}", pair.name, e);
+                    status_board.set(idx, IconChange::Error);
+                    icon_tx.send(status_board.overall()).await.ok();
+                    continue;
+                }
+
+                info!(
+                    "[{}] Exclude targets changed: {} paths start syncing, {} paths stop syncing.",
+                    pair.name,
+                    change.starts.len(),
+                    change.stops.len()
+                );
+                for p in change.starts.iter() {
+                    info!("[{}]   + {:?}", pair.name, p);
+                }
+                for p in change.stops.iter() {
+                    info!("[{}]   - {:?}", pair.name, p);
+                }
+
+                // ncs forgets the paths which stop syncing, so it doesn't compare them any more.
+                {
+                    let mut pr_ref = pair.public_resource.lock().map_err(|_| LockError)?;
+                    let (checker, excludes) = (&pair.local_info.exc_checker, &pair.excludes);
+                    let pruned = exclude::prune_cached_tree(&pr_ref.root, |e| {
+                        checker.judge(&e.managed_path) && excludes.judge_remote(e)
+                    });
+                    match pruned {
+                        Ok(Some((root, dropped))) => {
+                            pr_ref.root = root;
+                            info!(
+                                "[{}] {} paths are dropped from the cached tree.",
+                                pair.name,
+                                dropped.len()
+                            );
+                        }
+                        Ok(None) => (),
+                        Err(e) => warn!("[{}] The cached tree is not pruned: {}", pair.name, e),
+                    }
+                }
+
+                // queued in the channel of the pair, so the main loop isn't blocked.
+                // a failed pull goes to the retry queue like the other pulls.
+                let tx = pair.com_tx.clone();
+                let local_info = pair.local_info.clone();
+                let excludes = pair.excludes.clone();
+                tokio::spawn(logging::with_context(async move {
+                    for managed_path in change.local_starts {
+                        logging::set_context(
+                            Some(&pair_config.name),
+                            Some("push"),
+                            Some(&managed_path),
+                        );
+                        let target = local_info.root_path_cano.join(&managed_path);
+                        let res = ncsync_daemon::push(
+                            &target,
+                            &tx,
+                            true,
+                            true,
+                            &local_info,
+                            &excludes,
+                            &pair_config,
+                        )
+                        .await;
+                        if let Err(e) = res {
+                            warn!("[{}] Push {:?}: {}", pair_config.name, managed_path, e);
+                        }
+                    }
+                    for managed_path in change.remote_starts {
+                        tx.send(Command::PullEvent {
+                            target: local_info.root_path_cano.join(&managed_path),
+                            is_recursive: true,
+                            stash: false,
+                        })
+                        .await
+                        .ok();
+                    }
+                }));
+                icon_tx.send(status_board.overall()).await.ok();
             }
             Command::UpdateConfigFile => {
-                icon_tx.send(IconChange::Load).await.ok();
                 info!("Update Config file.");
-                info!("Rebooting...");
-                retry = Ok(true);
-                break;
+                let new_config = match config::Config::load_conf() {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("[config error] conf.ini is not applied: {}", e);
+                        continue;
+                    }
+                };
+                let current = applied_config.as_ref().unwrap_or(config);
+                let diff = current.diff(&new_config);
+                debug!("{:?}", diff);
+                if diff.reboot {
+                    icon_tx.send(IconChange::Load).await.ok();
+                    info!("Rebooting...");
+                    retry = Ok(true);
+                    break;
+                }
+                // a part which fails keeps running on the old settings, and the
+                // whole conf.ini is compared against them again on the next change.
+                let mut all_applied = true;
+                if diff.rust_log || diff.log_files {
+                    match logging::prepare_logging(log_handle, &logfile_paths, &new_config) {
+                        Ok(_) => info!("RUST_LOG: {}", new_config.rust_log),
+                        Err(e) => {
+                            warn!("[config error] The logger is not rebuilt: {}", e);
+                            all_applied = false;
+                        }
+                    }
+                }
+                if diff.autostash_keep_span {
+                    for pair in pairs.iter_mut() {
+                        let mut local_info = pair.local_info.clone();
+                        local_info.set_autostash_keep_span(new_config.autostash_keep_span);
+                        set_local_info(pair, local_info);
+                    }
+                    info!("AUTOSTASH_KEEP_SPAN: {}", new_config.autostash_keep_span);
+                }
+                if diff.retry {
+                    retry_policy = new_config.retry.clone();
+                    info!("Retry policy: {:?}", retry_policy);
+                }
+                if diff.client {
+                    let rebuilt = new_config.make_client().and_then(|new_client| {
+                        let local_infos = pairs
+                            .iter()
+                            .map(|pair| {
+                                let mut local_info = LocalInfo::new(
+                                    pair.pair_config.local_root.clone(),
+                                    new_client.clone(),
+                                )?;
+                                local_info.set_autostash_keep_span(new_config.autostash_keep_span);
+                                Ok(local_info)
+                            })
+                            .collect::<Result<Vec<_>>>()?;
+                        Ok((new_client, local_infos))
+                    });
+                    match rebuilt {
+                        Ok((new_client, local_infos)) => {
+                            client = new_client;
+                            for (idx, (pair, local_info)) in
+                                pairs.iter_mut().zip(local_infos).enumerate()
+                            {
+                                set_local_info(pair, local_info);
+
+                                // the watchers and the listener keep the old LocalInfo and
+                                // client, so start them again.
+                                let restarted = restart_watching(pair);
+                                if let Err(e) = restarted {
+                                    error!("[{}] The pair is stopped: {:?}", pair.name, e);
+                                    status_board.set(idx, IconChange::Error);
+                                }
+                            }
+                            icon_tx.send(status_board.overall()).await.ok();
+                            info!("HTTP client is rebuilt.");
+                        }
+                        Err(e) => {
+                            warn!("[config error] The HTTP client is not rebuilt: {}", e);
+                            all_applied = false;
+                        }
+                    }
+                }
+                if all_applied {
+                    applied_config = Some(new_config);
+                }
             }
             Command::HardRepair => {
                 icon_tx.send(IconChange::Load).await.ok();
-                info!("Hard Repair Start.");
-                drop(root_watcher);
-                drop(meta_watcher);
-                com_rx.close();
-
-                /*
-                nclisten_handle.abort();
-                watching_handle.abort();
-                updateexcfile_handle.abort();
-                updateconfigfile_handle.abort();
-                control_handle.abort();
-                */
-                repair::all_delete(&local_info)?;
+                info!("[{}] Hard Repair Start.", pair.name);
+                pair.local_watching.stop();
+                repair::all_delete(&pair.local_info)?;
+                // the cache of this pair was deleted, so it isn't saved again.
+                hard_repaired = Some(idx);
                 info!("Rebooting...");
-                return Ok(true);
+                retry = Ok(true);
+                break;
             }
             Command::NormalRepair => {
                 icon_tx.send(IconChange::Load).await.ok();
-                info!("Normal Repair Start");
-                let events = {
-                    let mut pr_ref = public_resource.lock().map_err(|_| LockError)?;
-                    get_ncevents(&nc_info, &local_info, &mut pr_ref.nc_state).await?
-                };
-                repair::normal_repair(&local_info, &nc_info, &public_resource, events).await?;
+                for pair in pairs.iter() {
+                    info!("[{}] Normal Repair Start", pair.name);
+                    let events = {
+                        let mut pr_ref = pair.public_resource.lock().map_err(|_| LockError)?;
+                        get_ncevents(&pair.nc_info, &pair.local_info, &mut pr_ref.nc_state).await?
+                    };
+                    let events = scope_ncevents(&pair.pair_config, events);
+                    repair::normal_repair(
+                        &pair.local_info,
+                        &pair.nc_info,
+                        &pair.public_resource,
+                        events,
+                    )
+                    .await?;
+                }
                 sleep(Duration::from_secs(20)).await;
                 info!("Rebooting...");
                 retry = Ok(true);
                 break;
             }
-            Command::NetworkConnect => match network_status {
+            Command::NetworkConnect => match pair.network_status {
                 NetworkStatus::Connect => (),
                 _ => {
-                    info!("Network Connection Restored.");
+                    info!("[{}] Network Connection Restored.", pair.name);
                     icon_tx.send(IconChange::Load).await.ok();
                     // Reconnect situation
                     let have_to_rerun = repair::soft_repair(
-                        &local_info,
-                        &nc_info,
-                        &public_resource,
-                        offline_locevent_que.drain(..).collect(),
-                        com_tx.clone(),
-                        &mut nc2l_cancel_map,
-                        &mut l2nc_cancel_set,
+                        &pair.local_info,
+                        &pair.nc_info,
+                        &pair.public_resource,
+                        pair.offline_locevent_que.drain(..).collect(),
+                        pair.com_tx.clone(),
+                        nc2l_cancel_map,
+                        l2nc_cancel_set,
                     )
                     .await?;
                     if have_to_rerun {
@@ -440,20 +1295,23 @@ Please fix conf.ini and connect to the Internet."
                         retry = Ok(true);
                         break;
                     } else {
-                        network_status = NetworkStatus::Connect;
-                        icon_tx.send(IconChange::Normal).await.ok();
+                        pair.network_status = NetworkStatus::Connect;
+                        replay_parked(pair);
+                        status_board.set(idx, IconChange::Normal);
+                        icon_tx.send(status_board.overall()).await.ok();
                         retry = Ok(false);
                     }
                 }
             },
-            Command::NetworkDisconnect => match network_status {
+            Command::NetworkDisconnect => match pair.network_status {
                 NetworkStatus::Connect => {
-                    info!("Lost Network Connection.");
-                    icon_tx.send(IconChange::Offline).await.ok();
+                    info!("[{}] Lost Network Connection.", pair.name);
+                    status_board.set(idx, IconChange::Offline);
+                    icon_tx.send(status_board.overall()).await.ok();
                     // disconnect situation
-                    nc2l_cancel_map = HashMap::new();
-                    l2nc_cancel_set = HashSet::new();
-                    network_status = NetworkStatus::Disconnect;
+                    *nc2l_cancel_map = HashMap::new();
+                    *l2nc_cancel_set = HashSet::new();
+                    pair.network_status = NetworkStatus::Disconnect;
                 }
                 _ => (),
             },
@@ -463,21 +1321,25 @@ Please fix conf.ini and connect to the Internet."
                 break;
             }
             Command::Error(e) => {
-                retry = Err(e);
+                status_board.set(idx, IconChange::Error);
+                retry = Err(anyhow!("[{}] {}", pair.name, e));
                 break;
             }
         }
     }
 
-    drop(root_watcher);
-    drop(meta_watcher);
+    drop(watchers);
+    for pair in pairs.iter_mut() {
+        pair.local_watching.stop();
+        pair.nclisten_handle.abort();
+    }
 
     // to close tasktray handle.
     let _ = tray_tx.send(TasktrayMessage::Nop);
     // to close ncsyncmes handle.
     let _ = ncsyncmes_tx.send(None);
 
-    com_rx.close();
+    main_rx.close();
 
     /*
     nclisten_handle.abort();
@@ -487,17 +1349,22 @@ Please fix conf.ini and connect to the Internet."
     control_handle.abort();
     */
 
-    let pr_ref = public_resource.lock().map_err(|_| LockError)?;
-    let json_entry = {
-        let r = pr_ref.root.lock().map_err(|_| LockError)?;
-        debug!("\n{}", r.get_tree());
-        root2json_entry(&r)?
-    };
-    save_cache(
-        pr_ref.nc_state.latest_activity_id.clone(),
-        json_entry,
-        &local_info,
-    )?;
+    for (idx, pair) in pairs.iter().enumerate() {
+        if hard_repaired == Some(idx) {
+            continue;
+        }
+        let pr_ref = pair.public_resource.lock().map_err(|_| LockError)?;
+        let json_entry = {
+            let r = pr_ref.root.lock().map_err(|_| LockError)?;
+            debug!("[{}]\n{}", pair.name, r.get_tree());
+            root2json_entry(&r)?
+        };
+        save_cache(
+            pr_ref.nc_state.latest_activity_id.clone(),
+            json_entry,
+            &pair.local_info,
+        )?;
+    }
 
     debug!("run func end.");
 
@@ -506,6 +1373,15 @@ Please fix conf.ini and connect to the Internet."
 
 use tokio::runtime;
 
+// exit codes of --init. Other failures exit with 1.
+const EXIT_OK: i32 = 0;
+const EXIT_MISSING_VALUE: i32 = 2;
+const EXIT_INVALID_CONFIG: i32 = 3;
+const EXIT_UNREACHABLE: i32 = 4;
+const EXIT_ALREADY_EXISTS: i32 = 5;
+const EXIT_INVALID_VALUE: i32 = 6;
+const EXIT_SAVE_FAILED: i32 = 7;
+
 fn main() -> Result<()> {
     let rt = runtime::Builder::new_multi_thread()
         .enable_all()
@@ -518,7 +1394,47 @@ fn main() -> Result<()> {
 }
 
 async fn async_main() -> Result<()> {
-    let mut config = unsafe { config::prepare_config_file()? };
+    let args = config::Args::parse(std::env::args().skip(1))?;
+    let repair_boot = args.repair;
+    let logout = args.logout;
+    let init = args.init;
+    config::init_layers(args)?;
+
+    if init {
+        let code = match config::init_unattended().await {
+            Ok(config::ValidateResult::Ok) => {
+                println!("{:?} is created.", config::conffile_path());
+                EXIT_OK
+            }
+            Ok(res) if res.is_fatal() => {
+                eprintln!("[config error] {}", res);
+                EXIT_INVALID_CONFIG
+            }
+            Ok(res) => {
+                eprintln!("[network error] {}", res);
+                EXIT_UNREACHABLE
+            }
+            Err(e) => {
+                eprintln!("[config error] {}", e);
+                match e {
+                    config::InitError::AlreadyExists(_) => EXIT_ALREADY_EXISTS,
+                    config::InitError::MissingValue(_) => EXIT_MISSING_VALUE,
+                    config::InitError::InvalidValue(_) => EXIT_INVALID_VALUE,
+                    config::InitError::SaveFailed(_) => EXIT_SAVE_FAILED,
+                }
+            }
+        };
+        std::process::exit(code);
+    }
+
+    if logout {
+        let mut config = config::Config::load_conf()?;
+        config.logout().await?;
+        println!("Logged out.");
+        return Ok(());
+    }
+
+    let mut config = unsafe { config::prepare_config_file().await? };
     let log_handle = logging::prepare_logging_without_logfile(&config)?;
 
     let (tray_tx, tray_rx) = tokio_mpsc::channel(128);
@@ -541,12 +1457,8 @@ async fn async_main() -> Result<()> {
         let tray_rx = Arc::new(Mutex::new(tray_rx));
         let ncsyncmes_rx = Arc::new(Mutex::new(ncsyncmes_rx));
         let mut loop_counter = 1;
-        let repair_boot = std::env::args()
-            .nth(1)
-            .map(|arg| &arg == "--repair")
-            .unwrap_or(false);
         loop {
-            let cntn_w = run(
+            let cntn_w = logging::with_context(run(
                 tray_tx.clone(),
                 tray_rx.clone(),
                 ncsyncmes_tx.clone(),
@@ -556,7 +1468,7 @@ async fn async_main() -> Result<()> {
                 loop_counter,
                 &config,
                 repair_boot,
-            )
+            ))
             .await;
 
             match cntn_w {
@@ -580,7 +1492,7 @@ async fn async_main() -> Result<()> {
                 }
             }
 
-            config = config::prepare_config_file()?;
+            config = config::prepare_config_file().await?;
             loop_counter += 1;
         }
         drop(tray_rx);
@@ -645,7 +1557,7 @@ enum TasktrayMessage {
     Exit,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum IconChange {
     Normal,
     Load,
@@ -844,11 +1756,11 @@ extern "system" fn wndproc(window: HWND, message: u32, wparam: WPARAM, lparam: L
                 match (wparam.0 as u32, lparam.0 as u32) {
                     (MSGID_SHOWLOG, _) => {
                         debug!("TASKTRAY SHOWLOG");
-                        open_notepad(logging::TMPLOGFILENAME.to_string());
+                        open_notepad(logging::tmp_logfile().to_string_lossy().to_string());
                     }
                     (MSGID_EDITCONF, _) => {
                         debug!("TASKTRAY EDITCONF");
-                        open_notepad(config::CONFFILENAME.to_string());
+                        open_notepad(config::conffile_path().to_string_lossy().to_string());
                     }
                     (MSGID_EDITEXCLUDE, _) => {
                         debug!("TASKTRAY EDITEXCLUDE");